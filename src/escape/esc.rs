@@ -1,16 +1,133 @@
+use bitflags::bitflags;
 use escape::EncodeEscape;
 use num::{self, ToPrimitive};
 use std;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Esc {
     Unspecified {
-        intermediate: Option<u8>,
+        /// Zero or more intermediate bytes, each in the 0x20-0x2f range.
+        /// ECMA-48 permits a run of these before the final byte; most
+        /// sequences carry at most one, but some (eg: the 96-character
+        /// set designations) carry more.
+        intermediate: Vec<u8>,
         /// The final character in the Escape sequence; this typically
         /// defines how to interpret the other parameters.
         control: u8,
     },
     Code(EscCode),
+    /// Designate a character set into one of the G0-G3 slots.  The
+    /// character set remains in the slot until a different one is
+    /// designated into it; `SingleShiftG2`/`SingleShiftG3` and the
+    /// locking shifts (not modeled here) control which slot is actually
+    /// in use for a given byte.
+    Designate {
+        slot: CharsetSlot,
+        charset: CharacterSet,
+    },
+}
+
+/// The four character set slots that can be designated into via
+/// `ESC (`, `ESC )`, `ESC *` and `ESC +` respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharsetSlot {
+    G0,
+    G1,
+    G2,
+    G3,
+}
+
+impl CharsetSlot {
+    fn intermediate(self) -> u8 {
+        match self {
+            CharsetSlot::G0 => b'(',
+            CharsetSlot::G1 => b')',
+            CharsetSlot::G2 => b'*',
+            CharsetSlot::G3 => b'+',
+        }
+    }
+
+    fn from_intermediate(intermediate: u8) -> Option<Self> {
+        match intermediate {
+            b'(' => Some(CharsetSlot::G0),
+            b')' => Some(CharsetSlot::G1),
+            b'*' => Some(CharsetSlot::G2),
+            b'+' => Some(CharsetSlot::G3),
+            _ => None,
+        }
+    }
+}
+
+/// The character sets that can be designated into a `CharsetSlot`.
+/// This includes the basic ASCII/DEC sets as well as the National
+/// Replacement Character Sets (NRCS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharacterSet {
+    Ascii,
+    UkNational,
+    DecLineDrawing,
+    DecAlternateCharacterSet,
+    DecAlternateSpecialCharacterSet,
+    DecSupplemental,
+    DecTechnical,
+    Dutch,
+    Finnish,
+    French,
+    FrenchCanadian,
+    German,
+    Italian,
+    NorwegianDanish,
+    Spanish,
+    Swedish,
+    Swiss,
+}
+
+impl CharacterSet {
+    fn final_byte(self) -> u8 {
+        match self {
+            CharacterSet::Ascii => b'B',
+            CharacterSet::UkNational => b'A',
+            CharacterSet::DecLineDrawing => b'0',
+            CharacterSet::DecAlternateCharacterSet => b'1',
+            CharacterSet::DecAlternateSpecialCharacterSet => b'2',
+            CharacterSet::DecSupplemental => b'<',
+            CharacterSet::DecTechnical => b'>',
+            CharacterSet::Dutch => b'4',
+            CharacterSet::Finnish => b'C',
+            CharacterSet::French => b'R',
+            CharacterSet::FrenchCanadian => b'Q',
+            CharacterSet::German => b'K',
+            CharacterSet::Italian => b'Y',
+            CharacterSet::NorwegianDanish => b'`',
+            CharacterSet::Spanish => b'Z',
+            CharacterSet::Swedish => b'7',
+            CharacterSet::Swiss => b'=',
+        }
+    }
+
+    fn from_final_byte(control: u8) -> Option<Self> {
+        Some(match control {
+            b'B' => CharacterSet::Ascii,
+            b'A' => CharacterSet::UkNational,
+            b'0' => CharacterSet::DecLineDrawing,
+            b'1' => CharacterSet::DecAlternateCharacterSet,
+            b'2' => CharacterSet::DecAlternateSpecialCharacterSet,
+            b'<' => CharacterSet::DecSupplemental,
+            b'>' => CharacterSet::DecTechnical,
+            b'4' => CharacterSet::Dutch,
+            b'C' | b'5' => CharacterSet::Finnish,
+            b'R' => CharacterSet::French,
+            b'Q' => CharacterSet::FrenchCanadian,
+            b'K' => CharacterSet::German,
+            b'Y' => CharacterSet::Italian,
+            b'`' | b'E' | b'6' => CharacterSet::NorwegianDanish,
+            b'Z' => CharacterSet::Spanish,
+            b'7' | b'H' => CharacterSet::Swedish,
+            b'=' => CharacterSet::Swiss,
+            _ => return None,
+        })
+    }
 }
 
 macro_rules! esc {
@@ -66,10 +183,10 @@ pub enum EscCode {
     /// DECPNM - Normal Keypad
     DecNormalKeyPad = esc!('>'),
 
-    /// Designate Character Set – DEC Line Drawing
-    DecLineDrawing = esc!('(', '0'),
-    /// Designate Character Set – US ASCII
-    AsciiCharacterSet = esc!('(', 'B'),
+    /// S7C1T - Select 7-bit representation for C1 control characters
+    Select7BitC1 = esc!(' ', 'F'),
+    /// S8C1T - Select 8-bit representation for C1 control characters
+    Select8BitC1 = esc!(' ', 'G'),
 
     /// These are typically sent by the terminal when keys are pressed
     ApplicationModeArrowUpPress = esc!('O', 'A'),
@@ -84,17 +201,92 @@ pub enum EscCode {
     F4Press = esc!('O', 'S'),
 }
 
+/// A keyboard modifier, as used by `KeyEvent`.  Several modifiers may be
+/// held down at once, hence this is a bitset rather than a plain enum.
+bitflags! {
+    pub struct Modifiers: u8 {
+        const NONE = 0;
+        const SHIFT = 1<<0;
+        const ALT = 1<<1;
+        const CTRL = 1<<2;
+    }
+}
+
+/// A logical, non-printable key, decoded from the raw escape bytes that
+/// a terminal emits when the key is pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Function keys are 1-based: `F(1)` is F1.
+    F(u8),
+}
+
+/// A logical key press: the key itself plus whichever modifiers were
+/// held down when it was generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub modifiers: Modifiers,
+}
+
 impl Esc {
-    pub fn parse(intermediate: Option<u8>, control: u8) -> Self {
+    /// Decode this `Esc` into a logical `KeyEvent`, for the sequences
+    /// that a terminal emits when an application-cursor-key or a
+    /// SS3-introduced function key is pressed.  Returns `None` for any
+    /// `Esc` that does not represent a key press.  DECCKM/SS3 forms
+    /// never carry modifier parameters, so `modifiers` is always empty
+    /// here.  The modifier-bearing CSI forms such as `1;5A` are not
+    /// decoded into a `KeyEvent`.
+    pub fn as_key_event(&self) -> Option<KeyEvent> {
+        let key = match self {
+            Esc::Code(EscCode::ApplicationModeArrowUpPress) => KeyCode::Up,
+            Esc::Code(EscCode::ApplicationModeArrowDownPress) => KeyCode::Down,
+            Esc::Code(EscCode::ApplicationModeArrowLeftPress) => KeyCode::Left,
+            Esc::Code(EscCode::ApplicationModeArrowRightPress) => KeyCode::Right,
+            Esc::Code(EscCode::ApplicationModeHomePress) => KeyCode::Home,
+            Esc::Code(EscCode::ApplicationModeEndPress) => KeyCode::End,
+            Esc::Code(EscCode::F1Press) => KeyCode::F(1),
+            Esc::Code(EscCode::F2Press) => KeyCode::F(2),
+            Esc::Code(EscCode::F3Press) => KeyCode::F(3),
+            Esc::Code(EscCode::F4Press) => KeyCode::F(4),
+            _ => return None,
+        };
+        Some(KeyEvent {
+            key,
+            modifiers: Modifiers::NONE,
+        })
+    }
+
+    pub fn parse(intermediate: &[u8], control: u8) -> Self {
         Self::internal_parse(intermediate, control).unwrap_or_else(|_| Esc::Unspecified {
-            intermediate,
+            intermediate: intermediate.to_vec(),
             control,
         })
     }
 
-    fn internal_parse(intermediate: Option<u8>, control: u8) -> Result<Self, ()> {
-        let packed = match intermediate {
-            Some(high) => ((high as u16) << 8) | control as u16,
+    fn internal_parse(intermediate: &[u8], control: u8) -> Result<Self, ()> {
+        if let [i] = *intermediate {
+            if let Some(slot) = CharsetSlot::from_intermediate(i) {
+                if let Some(charset) = CharacterSet::from_final_byte(control) {
+                    return Ok(Esc::Designate { slot, charset });
+                }
+            }
+        }
+
+        // The packed u16 encoding used by `EscCode` can only represent a
+        // single intermediate byte; sequences with more than one fall
+        // through to `Esc::Unspecified`.
+        if intermediate.len() > 1 {
+            return Err(());
+        }
+
+        let packed = match intermediate.first() {
+            Some(&high) => ((high as u16) << 8) | control as u16,
             None => control as u16,
         };
 
@@ -102,6 +294,171 @@ impl Esc {
 
         Ok(Esc::Code(code))
     }
+
+    /// Renders this `Esc` as a human readable, round-trippable string
+    /// such as `\x1b(0` or `\x1bD`, with any non-printable intermediate
+    /// or final byte shown as `\xNN`.  The result can be parsed back
+    /// into an equal `Esc` via `Esc::from_escaped_string`.
+    pub fn to_escaped_string(&self) -> String {
+        let mut buf = Vec::new();
+        self.encode_escape(&mut buf)
+            .expect("writing to a Vec cannot fail");
+
+        let mut s = String::new();
+        s.push_str("\\x1b");
+        for &b in &buf[1..] {
+            // A literal backslash is printable but would be ambiguous
+            // with the `\xNN` escape introducer, so it is always
+            // hex-escaped too.
+            if b >= 0x20 && b < 0x7f && b != b'\\' {
+                s.push(b as char);
+            } else {
+                s.push_str(&format!("\\x{:02x}", b));
+            }
+        }
+        s
+    }
+
+    /// Parses the textual form produced by `to_escaped_string` back into
+    /// an `Esc`.
+    pub fn from_escaped_string(s: &str) -> Result<Esc, String> {
+        let mut bytes = Vec::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some('x') => {
+                        let hi = chars.next().ok_or("truncated \\x escape")?;
+                        let lo = chars.next().ok_or("truncated \\x escape")?;
+                        let byte = u8::from_str_radix(&format!("{}{}", hi, lo), 16)
+                            .map_err(|e| format!("invalid \\x escape: {}", e))?;
+                        bytes.push(byte);
+                    }
+                    Some(other) => return Err(format!("unknown escape \\{}", other)),
+                    None => return Err("truncated escape sequence".to_string()),
+                }
+            } else if c.is_ascii() {
+                bytes.push(c as u8);
+            } else {
+                return Err(format!("non-ascii character {:?} in escape sequence", c));
+            }
+        }
+
+        if bytes.first() != Some(&0x1b) {
+            return Err("escaped string must begin with \\x1b".to_string());
+        }
+
+        let rest = &bytes[1..];
+        if rest.is_empty() {
+            return Err("escaped string has no final byte".to_string());
+        }
+
+        let (intermediate, control) = rest.split_at(rest.len() - 1);
+        Ok(Esc::parse(intermediate, control[0]))
+    }
+}
+
+impl fmt::Display for Esc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_escaped_string())
+    }
+}
+
+/// The subset of terminal mode state that affects how a `KeyEvent`
+/// should be encoded: whether DECCKM (application cursor keys) is
+/// currently active.  There is no `KeyCode` for the numeric keypad yet,
+/// so DECPAM (application keypad) has nothing to affect here; add a
+/// field for it alongside a keypad `KeyCode` variant when that lands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TerminalModes {
+    pub application_cursor_keys: bool,
+}
+
+/// The CSI `~` parameter used to encode `F5`-`F20`; `F1`-`F4` always use
+/// the SS3 forms.  Function keys above `F20` have no CSI `~` encoding
+/// here and are rejected by `encode_key`.
+fn csi_function_key_param(n: u8) -> Option<u8> {
+    match n {
+        5 => Some(15),
+        6 => Some(17),
+        7 => Some(18),
+        8 => Some(19),
+        9 => Some(20),
+        10 => Some(21),
+        11 => Some(23),
+        12 => Some(24),
+        13 => Some(25),
+        14 => Some(26),
+        15 => Some(28),
+        16 => Some(29),
+        17 => Some(31),
+        18 => Some(32),
+        19 => Some(33),
+        20 => Some(34),
+        _ => None,
+    }
+}
+
+/// Encodes logical `KeyEvent`s into the escape bytes a terminal expects
+/// to receive from its keyboard, honoring whether DECCKM (application
+/// cursor keys) is active.  The keypad mode (DECPAM/DECPNM) is not
+/// consulted.  This is the inverse of `Esc::as_key_event`.
+pub struct KeyEncoder;
+
+impl KeyEncoder {
+    pub fn encode_key<W: std::io::Write>(
+        key: KeyEvent,
+        modes: TerminalModes,
+        w: &mut W,
+    ) -> Result<(), std::io::Error> {
+        if !key.modifiers.is_empty() {
+            // Encoding modifier parameters (the `1;5A`-style CSI forms)
+            // is not implemented yet; fail loudly rather than silently
+            // emitting the unmodified key.
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("encoding modifiers {:?} is not supported", key.modifiers),
+            ));
+        }
+
+        match key.key {
+            KeyCode::Up if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeArrowUpPress).encode_escape(w)
+            }
+            KeyCode::Up => w.write_all(b"\x1b[A"),
+            KeyCode::Down if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeArrowDownPress).encode_escape(w)
+            }
+            KeyCode::Down => w.write_all(b"\x1b[B"),
+            KeyCode::Right if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeArrowRightPress).encode_escape(w)
+            }
+            KeyCode::Right => w.write_all(b"\x1b[C"),
+            KeyCode::Left if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeArrowLeftPress).encode_escape(w)
+            }
+            KeyCode::Left => w.write_all(b"\x1b[D"),
+            KeyCode::Home if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeHomePress).encode_escape(w)
+            }
+            KeyCode::Home => w.write_all(b"\x1b[H"),
+            KeyCode::End if modes.application_cursor_keys => {
+                Esc::Code(EscCode::ApplicationModeEndPress).encode_escape(w)
+            }
+            KeyCode::End => w.write_all(b"\x1b[F"),
+            KeyCode::F(1) => Esc::Code(EscCode::F1Press).encode_escape(w),
+            KeyCode::F(2) => Esc::Code(EscCode::F2Press).encode_escape(w),
+            KeyCode::F(3) => Esc::Code(EscCode::F3Press).encode_escape(w),
+            KeyCode::F(4) => Esc::Code(EscCode::F4Press).encode_escape(w),
+            KeyCode::F(n) => match csi_function_key_param(n) {
+                Some(param) => write!(w, "\x1b[{}~", param),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("F{} has no known encoding", n),
+                )),
+            },
+        }
+    }
 }
 
 impl EncodeEscape for Esc {
@@ -128,13 +485,12 @@ impl EncodeEscape for Esc {
                 intermediate,
                 control,
             } => {
-                if let Some(i) = intermediate {
-                    let buf = [*i, *control];
-                    w.write_all(&buf)?;
-                } else {
-                    let buf = [*control];
-                    w.write_all(&buf)?;
-                }
+                w.write_all(intermediate)?;
+                w.write_all(&[*control])?;
+            }
+            Designate { slot, charset } => {
+                let buf = [slot.intermediate(), charset.final_byte()];
+                w.write_all(&buf)?;
             }
         };
         Ok(())
@@ -152,11 +508,9 @@ mod test {
     }
 
     fn parse(esc: &str) -> Esc {
-        let result = if esc.len() == 1 {
-            Esc::parse(None, esc.as_bytes()[0])
-        } else {
-            Esc::parse(Some(esc.as_bytes()[0]), esc.as_bytes()[1])
-        };
+        let bytes = esc.as_bytes();
+        let (intermediate, control) = bytes.split_at(bytes.len() - 1);
+        let result = Esc::parse(intermediate, control[0]);
 
         assert_eq!(encode(&result), format!("\x1b{}", esc));
 
@@ -165,7 +519,191 @@ mod test {
 
     #[test]
     fn test() {
-        assert_eq!(parse("(0"), Esc::Code(EscCode::DecLineDrawing));
-        assert_eq!(parse("(B"), Esc::Code(EscCode::AsciiCharacterSet));
+        assert_eq!(parse("c"), Esc::Code(EscCode::FullReset));
+        assert_eq!(parse("7"), Esc::Code(EscCode::DecSaveCursorPosition));
+    }
+
+    #[test]
+    fn test_c1_transmission_mode() {
+        assert_eq!(parse(" F"), Esc::Code(EscCode::Select7BitC1));
+        assert_eq!(parse(" G"), Esc::Code(EscCode::Select8BitC1));
+    }
+
+    #[test]
+    fn test_multiple_intermediates() {
+        // Not a real terminal sequence, but exercises a run of more than
+        // one intermediate byte, which the packed `EscCode` encoding
+        // cannot represent.
+        let parsed = Esc::parse(&[b'-', b'%'], b'q');
+        assert_eq!(
+            parsed,
+            Esc::Unspecified {
+                intermediate: vec![b'-', b'%'],
+                control: b'q',
+            }
+        );
+        assert_eq!(encode(&parsed), "\x1b-%q");
+    }
+
+    #[test]
+    fn test_as_key_event() {
+        assert_eq!(
+            parse("OA").as_key_event(),
+            Some(KeyEvent {
+                key: KeyCode::Up,
+                modifiers: Modifiers::NONE,
+            })
+        );
+        assert_eq!(
+            parse("OP").as_key_event(),
+            Some(KeyEvent {
+                key: KeyCode::F(1),
+                modifiers: Modifiers::NONE,
+            })
+        );
+        assert_eq!(parse("c").as_key_event(), None);
+    }
+
+    fn encode_key(key: KeyCode, modes: TerminalModes) -> String {
+        let mut res = Vec::new();
+        KeyEncoder::encode_key(
+            KeyEvent {
+                key,
+                modifiers: Modifiers::NONE,
+            },
+            modes,
+            &mut res,
+        ).unwrap();
+        String::from_utf8(res).unwrap()
+    }
+
+    #[test]
+    fn test_encode_key_cursor_modes() {
+        let normal = TerminalModes::default();
+        let application = TerminalModes {
+            application_cursor_keys: true,
+            ..Default::default()
+        };
+
+        assert_eq!(encode_key(KeyCode::Up, normal), "\x1b[A");
+        assert_eq!(encode_key(KeyCode::Up, application), "\x1bOA");
+        assert_eq!(encode_key(KeyCode::Home, normal), "\x1b[H");
+        assert_eq!(encode_key(KeyCode::Home, application), "\x1bOH");
+    }
+
+    #[test]
+    fn test_encode_key_function_keys() {
+        let modes = TerminalModes::default();
+        assert_eq!(encode_key(KeyCode::F(1), modes), "\x1bOP");
+        assert_eq!(encode_key(KeyCode::F(4), modes), "\x1bOS");
+        assert_eq!(encode_key(KeyCode::F(5), modes), "\x1b[15~");
+        assert_eq!(encode_key(KeyCode::F(12), modes), "\x1b[24~");
+        assert_eq!(encode_key(KeyCode::F(13), modes), "\x1b[25~");
+        assert_eq!(encode_key(KeyCode::F(20), modes), "\x1b[34~");
+    }
+
+    #[test]
+    fn test_encode_key_unencodable_function_key() {
+        let mut res = Vec::new();
+        let err = KeyEncoder::encode_key(
+            KeyEvent {
+                key: KeyCode::F(21),
+                modifiers: Modifiers::NONE,
+            },
+            TerminalModes::default(),
+            &mut res,
+        ).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn test_encode_key_rejects_modifiers() {
+        let mut res = Vec::new();
+        let err = KeyEncoder::encode_key(
+            KeyEvent {
+                key: KeyCode::Up,
+                modifiers: Modifiers::SHIFT,
+            },
+            TerminalModes::default(),
+            &mut res,
+        ).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn test_to_escaped_string() {
+        assert_eq!(Esc::Code(EscCode::FullReset).to_escaped_string(), "\\x1bc");
+        assert_eq!(
+            Esc::Designate {
+                slot: CharsetSlot::G0,
+                charset: CharacterSet::DecLineDrawing,
+            }.to_escaped_string(),
+            "\\x1b(0"
+        );
+        assert_eq!(
+            Esc::Code(EscCode::StringTerminator).to_escaped_string(),
+            "\\x1b\\x5c"
+        );
+    }
+
+    #[test]
+    fn test_escaped_string_round_trip() {
+        for esc in &[
+            Esc::Code(EscCode::FullReset),
+            Esc::Code(EscCode::Select7BitC1),
+            Esc::Designate {
+                slot: CharsetSlot::G1,
+                charset: CharacterSet::German,
+            },
+            Esc::Unspecified {
+                intermediate: vec![b'-', b'%'],
+                control: b'q',
+            },
+        ] {
+            let text = esc.to_escaped_string();
+            assert_eq!(&Esc::from_escaped_string(&text).unwrap(), esc);
+            assert_eq!(format!("{}", esc), text);
+        }
+    }
+
+    #[test]
+    fn test_designate_charset() {
+        assert_eq!(
+            parse("(0"),
+            Esc::Designate {
+                slot: CharsetSlot::G0,
+                charset: CharacterSet::DecLineDrawing,
+            }
+        );
+        assert_eq!(
+            parse("(B"),
+            Esc::Designate {
+                slot: CharsetSlot::G0,
+                charset: CharacterSet::Ascii,
+            }
+        );
+        assert_eq!(
+            parse(")K"),
+            Esc::Designate {
+                slot: CharsetSlot::G1,
+                charset: CharacterSet::German,
+            }
+        );
+        assert_eq!(
+            parse("*<"),
+            Esc::Designate {
+                slot: CharsetSlot::G2,
+                charset: CharacterSet::DecSupplemental,
+            }
+        );
+        assert_eq!(
+            parse("+Q"),
+            Esc::Designate {
+                slot: CharsetSlot::G3,
+                charset: CharacterSet::FrenchCanadian,
+            }
+        );
     }
 }